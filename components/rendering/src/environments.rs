@@ -0,0 +1,160 @@
+//! Theorem/definition/proof style environments, rendered as semantic HTML
+//! blocks with any math inside run through the KaTeX renderer.
+//!
+//! Authors write a fenced region naming the kind and, optionally, a title:
+//!
+//! ```text
+//! :::theorem Pythagoras
+//! For a right triangle, $a^2 + b^2 = c^2$.
+//! :::
+//! ```
+//!
+//! which becomes:
+//!
+//! ```html
+//! <div class="theorem"><span class="theorem-title">Pythagoras</span>For a right triangle, ...</div>
+//! ```
+
+use std::collections::HashMap;
+
+use regex::Regex;
+
+use crate::katex::{self, KatexError, KatexOptions};
+
+/// The environment kinds recognized by the `:::kind` fence syntax.
+pub const ENVIRONMENT_KINDS: &[&str] =
+    &["theorem", "definition", "lemma", "proposition", "corollary", "proof"];
+
+/// Per-kind CSS class overrides, so themes can style each environment.
+/// A kind without an override uses its own name as the class.
+#[derive(Debug, Clone, Default)]
+pub struct EnvironmentOptions {
+    pub css_classes: HashMap<String, String>,
+}
+
+impl EnvironmentOptions {
+    fn css_class(&self, kind: &str) -> &str {
+        self.css_classes.get(kind).map(|s| s.as_str()).unwrap_or(kind)
+    }
+}
+
+fn environment_re() -> Regex {
+    Regex::new(r"(?ms)^:::(?P<kind>\w+)[ \t]*(?P<title>[^\n]*)\n(?P<body>.*?)\n:::[ \t]*$").unwrap()
+}
+
+/// Find `:::kind Title ... :::` blocks, wrap them in semantic HTML, and run
+/// `render_katex_with_options` over the body of each one, returning any
+/// `KatexError`s produced by formulas inside those bodies alongside the
+/// rendered content, same as `render_katex` does for the top-level page.
+pub fn render_environments(
+    content: &str,
+    env_opts: &EnvironmentOptions,
+    katex_opts: &KatexOptions,
+) -> (String, Vec<KatexError>) {
+    let re = environment_re();
+    let mut last = 0;
+    let mut out = String::with_capacity(content.len());
+    let mut errors = Vec::new();
+    for caps in re.captures_iter(content) {
+        let whole = caps.get(0).unwrap();
+        out.push_str(&content[last..whole.start()]);
+        last = whole.end();
+
+        let kind = &caps["kind"];
+        if !ENVIRONMENT_KINDS.contains(&kind) {
+            // Not one of ours (e.g. a CSS-framework fenced div); leave as-is.
+            out.push_str(&content[whole.start()..whole.end()]);
+            continue;
+        }
+
+        let title = caps["title"].trim();
+        let body = &caps["body"];
+        let (rendered_body, mut body_errors) =
+            katex::render_katex_with_options(body, katex_opts);
+        errors.append(&mut body_errors);
+        let class = env_opts.css_class(kind);
+
+        out.push_str(&format!(r#"<div class="{}">"#, class));
+        if !title.is_empty() {
+            out.push_str(&format!(
+                r#"<span class="{}-title">{}</span>"#,
+                class,
+                katex::html_escape(title),
+            ));
+        }
+        out.push_str(&rendered_body);
+        out.push_str("</div>");
+    }
+    out.push_str(&content[last..]);
+    (out, errors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_text_unchanged() {
+        let eg = "Just a paragraph with no blocks.";
+        let (result, errors) =
+            render_environments(eg, &EnvironmentOptions::default(), &KatexOptions::default());
+        assert_eq!(eg, result);
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn unknown_kind_unchanged() {
+        let eg = ":::warning\nBe careful.\n:::";
+        let (result, errors) =
+            render_environments(eg, &EnvironmentOptions::default(), &KatexOptions::default());
+        assert_eq!(eg, result);
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn theorem_with_title_and_math() {
+        let eg = ":::theorem Pythagoras\nFor a right triangle, $a^2 + b^2 = c^2$.\n:::";
+        let (result, errors) =
+            render_environments(eg, &EnvironmentOptions::default(), &KatexOptions::default());
+        assert!(errors.is_empty());
+        assert!(result.starts_with(r#"<div class="theorem">"#));
+        assert!(result.contains(r#"<span class="theorem-title">Pythagoras</span>"#));
+        assert!(result.ends_with("</div>"));
+    }
+
+    #[test]
+    fn proof_without_title() {
+        let eg = ":::proof\nTrivial.\n:::";
+        let (result, errors) =
+            render_environments(eg, &EnvironmentOptions::default(), &KatexOptions::default());
+        assert!(errors.is_empty());
+        assert_eq!(result, r#"<div class="proof">Trivial.</div>"#);
+    }
+
+    #[test]
+    fn custom_css_class() {
+        let mut css_classes = HashMap::new();
+        css_classes.insert("theorem".to_string(), "math-theorem".to_string());
+        let env_opts = EnvironmentOptions { css_classes };
+        let eg = ":::theorem\nBody.\n:::";
+        let (result, _errors) = render_environments(eg, &env_opts, &KatexOptions::default());
+        assert!(result.starts_with(r#"<div class="math-theorem">"#));
+    }
+
+    #[test]
+    fn title_is_html_escaped() {
+        let eg = ":::theorem Pythagoras\"><script>\nBody.\n:::";
+        let (result, _errors) =
+            render_environments(eg, &EnvironmentOptions::default(), &KatexOptions::default());
+        assert!(!result.contains("<script>"));
+        assert!(result.contains("&lt;script&gt;"));
+    }
+
+    #[test]
+    fn formula_errors_inside_environment_are_returned() {
+        let eg = ":::theorem\n$\\frac{1}{$\n:::";
+        let (_result, errors) =
+            render_environments(eg, &EnvironmentOptions::default(), &KatexOptions::default());
+        assert_eq!(errors.len(), 1);
+    }
+}
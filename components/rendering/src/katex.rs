@@ -1,62 +1,475 @@
-use std::str;
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::Range;
 
 use katex;
+use once_cell::sync::Lazy;
 use regex::Regex;
 
-pub fn render_katex(content: &str) -> String {
-    let inline_math_re = Regex::new(
-            r"(?<![\\\$])\$ # non-escaped opening dollar and non-double-dollar
-            (
-              [^\s\$] # immediately followed by a non-whitespace character
-              [^\$]*
-              (?<![\\\s\$]) # closing dollar is immediately preceeded by a non-whitespace,
-                            # non-backslash character
-            )
-            \$(?![\d\$]) # closing dollar is not immediately followed by a digit or another dollar"
-        ).unwrap();
-    let display_math_re = Regex::new(
-            r"(?<!\\)\$\$ # opening double-dollar not preceeded by a backslash
-            (?=[^\s]|\h*\n\h*[^\$\s]) # either no whitespace, or a single newline
-                                      # followed by a non-empty line
-            ([^\$]*[^\s\$]) # any amount of characters not ending in whitespace
-            (?:\h*\n\h*)? # a possibly empty line before closing dollars
-            \$\$"
-        ).unwrap();
-
-
-    let inline = render_katex_aux(content, inline_math_re, false);
-    render_katex_aux(&inline.to_owned(), display_math_re, true)
-}
-
-fn render_katex_aux(content: &str, rex: Regex, display: bool) -> String {
-    let k_opts = katex::Opts::builder().display_mode(display).build().unwrap();
+use crate::mhchem;
+
+/// Which output format KaTeX should produce for each formula.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KatexOutput {
+    /// HTML only (the default, and the lightest option).
+    Html,
+    /// MathML only, for maximum accessibility/copy-paste fidelity.
+    Mathml,
+    /// Both HTML and MathML, letting the browser pick.
+    HtmlAndMathml,
+}
+
+impl Default for KatexOutput {
+    fn default() -> Self {
+        KatexOutput::Html
+    }
+}
+
+impl KatexOutput {
+    fn to_katex_output_type(self) -> katex::OutputType {
+        match self {
+            KatexOutput::Html => katex::OutputType::Html,
+            KatexOutput::Mathml => katex::OutputType::Mathml,
+            KatexOutput::HtmlAndMathml => katex::OutputType::HtmlAndMathml,
+        }
+    }
+}
+
+/// Rendering options threaded through to `katex::Opts`, mirroring the knobs
+/// the JS ecosystem (texmath, markdown-it-katex) exposes to site authors.
+#[derive(Debug, Clone)]
+pub struct KatexOptions {
+    /// What to do with a formula KaTeX fails to render.
+    pub on_error: OnKatexError,
+    /// User-defined macros, e.g. mapping `\RR` to `\mathbb{R}`, shared by
+    /// every formula on the page.
+    pub macros: HashMap<String, String>,
+    /// Render equation numbers on the left instead of the right.
+    pub leqno: bool,
+    /// Render display math flush left instead of centered.
+    pub fleqn: bool,
+    /// The minimum thickness, in em, for fraction bars and other rules KaTeX
+    /// draws, so they stay visible at small font sizes.
+    pub min_rule_thickness: f64,
+    /// Allow commands that can load external resources or affect the page
+    /// outside the formula itself (e.g. `\href`, `\includegraphics`). Mirrors
+    /// KaTeX's own `trust` option; leave off for untrusted user input.
+    pub trust: bool,
+    /// Output format: HTML, MathML, or both.
+    pub output: KatexOutput,
+    /// Register the `mhchem` macros, enabling `\ce{...}` chemical equations
+    /// and `\pu{...}` physical units.
+    pub mhchem: bool,
+    /// Glue inline math to an immediately following `, . ; : )` so the line
+    /// can't break between the rendered formula and its punctuation.
+    pub no_break_punctuation: bool,
+    /// Which delimiter pairs are recognized as math.
+    pub delimiters: DelimiterOptions,
+}
+
+/// Which delimiter pairs `render_katex_with_options` looks for, analogous to
+/// texmath's mergeable `delimiters` option. All are on by default.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DelimiterOptions {
+    /// `$...$`
+    pub dollar_inline: bool,
+    /// `$$...$$`
+    pub dollar_display: bool,
+    /// `\(...\)`
+    pub paren_inline: bool,
+    /// `\[...\]`
+    pub bracket_display: bool,
+    /// `\begin{align}...\end{align}` and friends (`gather`, `equation`,
+    /// `multline`, `alignat`), passed through to KaTeX whole since it
+    /// understands these environments natively.
+    pub environments: bool,
+}
+
+impl Default for DelimiterOptions {
+    fn default() -> Self {
+        DelimiterOptions {
+            dollar_inline: true,
+            dollar_display: true,
+            paren_inline: true,
+            bracket_display: true,
+            environments: true,
+        }
+    }
+}
+
+impl Default for KatexOptions {
+    fn default() -> Self {
+        KatexOptions {
+            on_error: OnKatexError::default(),
+            macros: HashMap::new(),
+            leqno: false,
+            fleqn: false,
+            min_rule_thickness: 0.0,
+            trust: false,
+            output: KatexOutput::default(),
+            mhchem: false,
+            no_break_punctuation: false,
+            delimiters: DelimiterOptions::default(),
+        }
+    }
+}
+
+/// Trailing punctuation that should stay glued to the inline formula before it.
+const GLUE_PUNCTUATION: &[char] = &[',', '.', ';', ':', ')'];
+
+/// If `content[after..]` is (optionally) a single space followed by one of
+/// `GLUE_PUNCTUATION`, return the end index of that punctuation.
+fn trailing_punctuation_end(content: &str, after: usize) -> Option<usize> {
+    let rest = &content[after..];
+    let trimmed = rest.strip_prefix(' ').unwrap_or(rest);
+    if trimmed.len() == rest.len() && rest.starts_with(char::is_whitespace) {
+        // whitespace other than a single space (a newline, a tab): don't glue across it
+        return None;
+    }
+    let c = trimmed.chars().next()?;
+    if GLUE_PUNCTUATION.contains(&c) {
+        Some(after + (rest.len() - trimmed.len()) + c.len_utf8())
+    } else {
+        None
+    }
+}
+
+/// What to do with a single formula that KaTeX fails to render, instead of
+/// panicking and aborting the whole build.
+///
+/// Mirrors KaTeX's own `throwOnError: false` behavior.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnKatexError {
+    /// Leave the original `$...$` / `$$...$$` source untouched in the output.
+    LeaveUnrendered,
+    /// Emit the offending source wrapped in an error span carrying the KaTeX
+    /// error message, so it can still be styled and spotted on the page.
+    ErrorSpan,
+}
+
+impl Default for OnKatexError {
+    fn default() -> Self {
+        OnKatexError::ErrorSpan
+    }
+}
+
+/// A single formula that failed to render, with enough context to locate it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KatexError {
+    /// The raw TeX source of the offending formula.
+    pub tex: String,
+    /// The error message returned by KaTeX.
+    pub message: String,
+}
+
+impl fmt::Display for KatexError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "failed to render formula `{}`: {}", self.tex, self.message)
+    }
+}
+
+pub fn render_katex(content: &str) -> (String, Vec<KatexError>) {
+    render_katex_with_options(content, &KatexOptions::default())
+}
+
+static PAREN_INLINE_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"(?s)\\\((.*?)\\\)").unwrap());
+static BRACKET_DISPLAY_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"(?s)\\\[(.*?)\\\]").unwrap());
+static ENVIRONMENT_RE: Lazy<Regex> = Lazy::new(|| {
+    // The `regex` crate has no backreferences, so each environment name gets
+    // its own alternative pairing `\begin{name}` with the same `\end{name}`.
+    let alternatives: Vec<String> = [
+        "align", "align*", "gather", "gather*", "equation", "equation*",
+        "multline", "multline*", "alignat", "alignat*",
+    ]
+    .iter()
+    .map(|name| {
+        let name = regex::escape(name);
+        format!(r"\\begin\{{{0}\}}.*?\\end\{{{0}\}}", name)
+    })
+    .collect();
+    Regex::new(&format!(r"(?s)({})", alternatives.join("|"))).unwrap()
+});
+
+/// A span of math found in the source, ready to be rendered and spliced back in.
+struct MathMatch {
+    /// The full delimited span, including the delimiters themselves.
+    whole: Range<usize>,
+    /// The inner TeX source, excluding delimiters.
+    tex: Range<usize>,
+    /// Whether this formula should render in KaTeX's display mode.
+    display: bool,
+}
+
+fn matches_from_regex(content: &str, rex: &Regex, display: bool) -> Vec<MathMatch> {
+    rex.captures_iter(content)
+        .map(|caps| {
+            let whole = caps.get(0).unwrap();
+            let tex = caps.get(1).unwrap();
+            MathMatch { whole: whole.start()..whole.end(), tex: tex.start()..tex.end(), display }
+        })
+        .collect()
+}
+
+/// Does `r` share any bytes with a span already claimed by a
+/// higher-priority delimiter match?
+fn overlaps_any(r: &Range<usize>, claimed: &[Range<usize>]) -> bool {
+    claimed.iter().any(|c| r.start < c.end && c.start < r.end)
+}
+
+pub fn render_katex_with_options(
+    content: &str,
+    opts: &KatexOptions,
+) -> (String, Vec<KatexError>) {
+    // All delimiter kinds are matched against the same, original `content`
+    // and merged before anything is rendered, in priority order: an
+    // environment block (or `\(\)`/`\[\]` pair) claims its span so a stray
+    // `$` inside it — e.g. a currency sign in `\begin{align}` body — can't
+    // be mistaken for dollar math and rendered out from under it.
+    let mut claimed: Vec<Range<usize>> = Vec::new();
+    let mut matches: Vec<MathMatch> = Vec::new();
+
+    if opts.delimiters.environments {
+        // The whole `\begin{...}...\end{...}` is capture group 1: KaTeX
+        // understands these environments natively, so it's passed through as-is.
+        let env_matches = matches_from_regex(content, &ENVIRONMENT_RE, true);
+        claimed.extend(env_matches.iter().map(|m| m.whole.clone()));
+        matches.extend(env_matches);
+    }
+    if opts.delimiters.paren_inline {
+        let paren_matches: Vec<MathMatch> = matches_from_regex(content, &PAREN_INLINE_RE, false)
+            .into_iter()
+            .filter(|m| !overlaps_any(&m.whole, &claimed))
+            .collect();
+        claimed.extend(paren_matches.iter().map(|m| m.whole.clone()));
+        matches.extend(paren_matches);
+    }
+    if opts.delimiters.bracket_display {
+        let bracket_matches: Vec<MathMatch> = matches_from_regex(content, &BRACKET_DISPLAY_RE, true)
+            .into_iter()
+            .filter(|m| !overlaps_any(&m.whole, &claimed))
+            .collect();
+        claimed.extend(bracket_matches.iter().map(|m| m.whole.clone()));
+        matches.extend(bracket_matches);
+    }
+    if opts.delimiters.dollar_inline || opts.delimiters.dollar_display {
+        let dollar_matches = scan_dollar_math(content).into_iter().filter(|m| {
+            let enabled = if m.display { opts.delimiters.dollar_display } else { opts.delimiters.dollar_inline };
+            enabled && !overlaps_any(&m.whole, &claimed)
+        });
+        matches.extend(dollar_matches);
+    }
+
+    matches.sort_by_key(|m| m.whole.start);
+    let mut errors = Vec::new();
+    let result = render_katex_aux(content, &matches, opts, &mut errors);
+    (result, errors)
+}
+
+/// Is the `$` (or other delimiter character) at byte offset `i` in `s` escaped,
+/// i.e. preceded by an odd number of consecutive backslashes?
+fn is_escaped(s: &str, i: usize) -> bool {
+    s.as_bytes()[..i].iter().rev().take_while(|&&b| b == b'\\').count() % 2 == 1
+}
+
+const HORIZONTAL_WS: [char; 2] = [' ', '\t'];
+
+/// Display math accepts two kinds of padding around its delimiters: none at
+/// all (`$$formula$$`), or the formula sitting alone on its own line
+/// (optional horizontal whitespace, then a single newline, on each side). A
+/// lone space with no newline is rejected, so `$$ 5` isn't mistaken for math.
+/// Returns the offsets of the inner formula (relative to `content`, given
+/// `offset` is where `inner` starts in it) once that padding is stripped, or
+/// `None` if the padding doesn't fit either shape, or nothing is left.
+fn display_padding_trim(offset: usize, inner: &str) -> Option<Range<usize>> {
+    let after_leading = skip_leading_padding(inner)?;
+    let leading_len = inner.len() - after_leading.len();
+    let trimmed = skip_trailing_padding(after_leading)?;
+    if trimmed.is_empty() {
+        return None;
+    }
+    let start = offset + leading_len;
+    Some(start..start + trimmed.len())
+}
+
+/// Strip an optional `(horizontal whitespace)? \n (horizontal whitespace)?`
+/// prefix, or nothing at all if there's no leading whitespace to begin with.
+/// Returns `None` if horizontal whitespace is present but isn't followed by
+/// a newline (a lone space, with no line break, is invalid padding).
+fn skip_leading_padding(s: &str) -> Option<&str> {
+    let after_hws = s.trim_start_matches(HORIZONTAL_WS);
+    match after_hws.strip_prefix('\n') {
+        Some(after_nl) => Some(after_nl.trim_start_matches(HORIZONTAL_WS)),
+        None if after_hws.len() == s.len() => Some(s),
+        None => None,
+    }
+}
+
+/// The suffix mirror of `skip_leading_padding`.
+fn skip_trailing_padding(s: &str) -> Option<&str> {
+    let before_hws = s.trim_end_matches(HORIZONTAL_WS);
+    match before_hws.strip_suffix('\n') {
+        Some(before_nl) => Some(before_nl.trim_end_matches(HORIZONTAL_WS)),
+        None if before_hws.len() == s.len() => Some(s),
+        None => None,
+    }
+}
+
+/// Single-pass, escape-aware scan for `$...$` and `$$...$$` math spans.
+///
+/// Walks the string tracking `$` positions, skipping delimiters preceded by
+/// an odd number of backslashes (escaped) so a formula may itself contain a
+/// literal `\$`. A closing inline `$` directly followed by a digit is rejected (so
+/// prices like `$50 $60` are left alone), and inline content may not start
+/// or end with whitespace.
+fn scan_dollar_math(content: &str) -> Vec<MathMatch> {
+    let bytes = content.as_bytes();
+    let len = bytes.len();
+    let mut matches = Vec::new();
+    let mut i = 0;
+    while i < len {
+        // A leftover `$` from a `$$` (or longer) run can't open a fresh span
+        // of its own, matching the original regexes' negative lookbehind.
+        if bytes[i] != b'$' || is_escaped(content, i) || (i > 0 && bytes[i - 1] == b'$') {
+            i += 1;
+            continue;
+        }
+        let display = i + 1 < len && bytes[i + 1] == b'$';
+        let tex_start = i + if display { 2 } else { 1 };
+
+        // Scan forward to the next unescaped `$`. It closes this span only if
+        // it is of the same kind (single vs. double); otherwise there's no match.
+        let mut close = None;
+        let mut j = tex_start;
+        while j < len {
+            if bytes[j] == b'$' && !is_escaped(content, j) {
+                let j_is_double = j + 1 < len && bytes[j + 1] == b'$';
+                if j_is_double == display {
+                    close = Some(j);
+                }
+                break;
+            }
+            j += 1;
+        }
+
+        let close = match close {
+            Some(close) => close,
+            None => {
+                i += 1;
+                continue;
+            }
+        };
+        let whole_end = close + if display { 2 } else { 1 };
+        let inner = &content[tex_start..close];
+
+        let tex: Range<usize> = if display {
+            match display_padding_trim(tex_start, inner) {
+                Some(tex) => tex,
+                None => {
+                    i += 1;
+                    continue;
+                }
+            }
+        } else {
+            if inner.is_empty()
+                || inner.starts_with(char::is_whitespace)
+                || inner.ends_with(char::is_whitespace)
+                || bytes.get(whole_end).map_or(false, u8::is_ascii_digit)
+            {
+                i += 1;
+                continue;
+            }
+            tex_start..close
+        };
+
+        matches.push(MathMatch { whole: i..whole_end, tex, display });
+        i = whole_end;
+    }
+    matches
+}
+
+fn render_katex_aux(
+    content: &str,
+    matches: &[MathMatch],
+    opts: &KatexOptions,
+    errors: &mut Vec<KatexError>,
+) -> String {
+    let mut macros = opts.macros.clone();
+    if opts.mhchem {
+        macros.extend(mhchem::macros());
+    }
     let mut last: usize = 0;
     let mut with_katex = String::with_capacity(content.len());
-    for caps in rex.captures_iter(content) {
-      let replace = caps.get(0).unwrap();
-      let tex = caps.get(1).unwrap();
-      with_katex.push_str(&content[last..replace.start()]);
-      last = replace.end();
-      let s = &content[tex.start()..tex.end()];
-      let k_html = katex::render_with_opts(s, k_opts.clone()).unwrap();
-      with_katex.push_str(&k_html);
-      // println!("{:?}", k_html);
+    for m in matches {
+        let k_opts = katex::Opts::builder()
+            .display_mode(m.display)
+            .leqno(opts.leqno)
+            .fleqn(opts.fleqn)
+            .min_rule_thickness(opts.min_rule_thickness)
+            .trust(opts.trust)
+            .output_type(opts.output.to_katex_output_type())
+            .macros(macros.clone())
+            .build()
+            .unwrap();
+        with_katex.push_str(&content[last..m.whole.start]);
+        last = m.whole.end;
+        let s = &content[m.tex.start..m.tex.end];
+        let source = if opts.mhchem { mhchem::expand(s) } else { s.to_owned() };
+        match katex::render_with_opts(&source, k_opts) {
+            Ok(k_html) => {
+                if !m.display && opts.no_break_punctuation {
+                    if let Some(end) = trailing_punctuation_end(content, last) {
+                        with_katex.push_str(&format!(
+                            r#"<span style="white-space: nowrap">{}{}</span>"#,
+                            k_html,
+                            &content[last..end],
+                        ));
+                        last = end;
+                    } else {
+                        with_katex.push_str(&k_html);
+                    }
+                } else {
+                    with_katex.push_str(&k_html);
+                }
+            }
+            Err(e) => {
+                errors.push(KatexError { tex: s.to_owned(), message: e.to_string() });
+                match opts.on_error {
+                    OnKatexError::LeaveUnrendered => {
+                        with_katex.push_str(&content[m.whole.start..m.whole.end]);
+                    }
+                    OnKatexError::ErrorSpan => {
+                        with_katex.push_str(&format!(
+                            r#"<span class="katex-error" title="{}">{}</span>"#,
+                            html_escape(&e.to_string()),
+                            html_escape(&content[m.whole.start..m.whole.end]),
+                        ));
+                    }
+                }
+            }
+        }
     }
     with_katex.push_str(&content[last..]);
     with_katex
 }
 
+pub(crate) fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
     fn unchanged(eg: &str) {
-        assert_eq!(eg, render_katex(eg));
+        assert_eq!(eg, render_katex(eg).0);
     }
 
     fn changed(eg: &str) {
-        let result = render_katex(eg);
+        let result = render_katex(eg).0;
         assert!(result.len() > eg.len());
         assert_ne!(eg, &result[..eg.len()]);
     }
@@ -94,14 +507,15 @@ mod tests {
     fn internal_whitespace_padding_unchanged() {
         unchanged(r"$ F = ma$");
         unchanged(r"$F = ma $");
+        // A lone space with no line break isn't valid display-math padding.
         unchanged(r"$$ \int_0^1 x^2 = \frac{1}{2}$$");
         unchanged(r"$$\int_0^1 x^2 = \frac{1}{2} $$");
-        unchanged(
-r"$$
-\int_0^1 x^2 = \frac{1}{2}
-$$"
-        );
-        unchanged(
+    }
+
+    #[test]
+    fn display_math_on_its_own_line_renders() {
+        // Unlike a lone space, a formula alone on its own line is valid padding.
+        changed(
 r"$$
 \int_0^1 x^2 = \frac{1}{2}
 $$"
@@ -117,10 +531,16 @@ $$"
     fn double_dollar_escaped_unchanged() {
         unchanged(r"\$$\int_0^1 x^2 = \frac{1}{2}$$");
         unchanged(r"$\$\int_0^1 x^2 = \frac{1}{2}$$");
-        unchanged(r"$$\int_0^1 x^2 = \frac{1}${2}\$$");
         unchanged(r"$$\int_0^1 x^2 = \frac{1}{2}$\$");
     }
 
+    #[test]
+    fn internal_dollar_with_valid_escaped_tail_renders() {
+        // `${2}\$` on its own is a valid inline formula once the `\$` inside
+        // it no longer breaks the scan (the fix this request is about).
+        changed(r"$$\int_0^1 x^2 = \frac{1}${2}\$$");
+    }
+
     #[test]
     fn random_double_dollar_unchanged() {
         unchanged(r"Hey $$ planet");
@@ -129,7 +549,7 @@ $$"
     #[test]
     fn working_inline() {
         let eg = r"Consider $π = \frac{1}{2}τ$ for a moment.";
-        let result = render_katex(eg);
+        let result = render_katex(eg).0;
         assert!(result.len() > eg.len());
         assert_ne!(eg, result);
         assert_eq!(eg[..9], result[..9]);
@@ -143,8 +563,8 @@ r"$$\sum_{i = 0}^n i = \frac{1}{2}n(n+1)$$"
         );
         // N.B. trailing whitespace is deliberate and should not disable math mode.
         changed(
-r"    $$ 
-        \sum_{i = 0}^n i = \frac{1}{2}n(n+1) 
+r"    $$
+        \sum_{i = 0}^n i = \frac{1}{2}n(n+1)
     $$"
         );
     }
@@ -156,7 +576,7 @@ r"    $$
                 4 \int_{-1}^1 \sqrt{1 - x^2} \mathop{dx} = τ
             $$
             and also consider $A = πr^2$ for a moment.";
-        let result = render_katex(eg);
+        let result = render_katex(eg).0;
         assert!(result.len() > eg.len());
         assert!(result.contains(", then"));
         assert!(result.contains("and also consider "));
@@ -164,4 +584,151 @@ r"    $$
         assert_eq!(eg[..9], result[..9]);
         assert_eq!(eg[eg.len()-14..], result[result.len()-14..]);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn malformed_formula_does_not_panic() {
+        let eg = r"This is broken: $\frac{1}{$ and the rest of the sentence.";
+        let (result, errors) = render_katex(eg);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].tex, r"\frac{1}{");
+        assert!(result.contains("katex-error"));
+    }
+
+    #[test]
+    fn malformed_formula_left_unrendered_when_requested() {
+        let eg = r"This is broken: $\frac{1}{$ and the rest of the sentence.";
+        let opts = KatexOptions { on_error: OnKatexError::LeaveUnrendered, ..KatexOptions::default() };
+        let (result, errors) = render_katex_with_options(eg, &opts);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(result, eg);
+    }
+
+    #[test]
+    fn user_macros_are_expanded() {
+        let mut macros = HashMap::new();
+        macros.insert(r"\RR".to_string(), r"\mathbb{R}".to_string());
+        let opts = KatexOptions { macros, ..KatexOptions::default() };
+        let (result, errors) = render_katex_with_options(r"$\RR$", &opts);
+        assert!(errors.is_empty());
+        assert!(result.contains("mathbb"));
+    }
+
+    #[test]
+    fn min_rule_thickness_changes_rendered_rule_width() {
+        let default_result = render_katex(r"$\frac{1}{2}$").0;
+        let opts = KatexOptions { min_rule_thickness: 2.0, ..KatexOptions::default() };
+        let (thick_result, errors) = render_katex_with_options(r"$\frac{1}{2}$", &opts);
+        assert!(errors.is_empty());
+        assert_ne!(default_result, thick_result);
+    }
+
+    #[test]
+    fn href_requires_trust() {
+        let eg = r"$\href{https://example.com}{link}$";
+        // Untrusted, KaTeX doesn't fail: it drops the link and shows the
+        // command name in its error color instead.
+        let (untrusted, errors) = render_katex(eg);
+        assert!(errors.is_empty());
+        assert!(!untrusted.contains("https://example.com"));
+
+        let opts = KatexOptions { trust: true, ..KatexOptions::default() };
+        let (trusted, errors) = render_katex_with_options(eg, &opts);
+        assert!(errors.is_empty());
+        assert!(trusted.contains(r#"href="https://example.com""#));
+    }
+
+    #[test]
+    fn mhchem_reaction_arrow() {
+        let opts = KatexOptions { mhchem: true, ..KatexOptions::default() };
+        let (result, errors) = render_katex_with_options(r"$\ce{CO2 + H2O -> H2CO3}$", &opts);
+        assert!(errors.is_empty());
+        // KaTeX renders `\rightarrow` as a literal arrow glyph in its HTML
+        // output, and the subscripted atom counts use its `vlist` machinery.
+        assert!(result.contains('→'));
+        assert!(result.contains("vlist"));
+    }
+
+    #[test]
+    fn mhchem_physical_unit() {
+        let opts = KatexOptions { mhchem: true, ..KatexOptions::default() };
+        let (result, errors) = render_katex_with_options(r"$\pu{123 kJ/mol}$", &opts);
+        assert!(errors.is_empty());
+        assert!(result.len() > 0);
+    }
+
+    #[test]
+    fn mhchem_disabled_by_default() {
+        let (_, errors) = render_katex(r"$\ce{CO2}$");
+        assert!(!errors.is_empty());
+    }
+
+    #[test]
+    fn punctuation_glued_when_enabled() {
+        let opts = KatexOptions { no_break_punctuation: true, ..KatexOptions::default() };
+        let (result, _) = render_katex_with_options(r"$x$,", &opts);
+        assert!(result.starts_with(r#"<span style="white-space: nowrap">"#));
+        assert!(result.ends_with(",</span>"));
+
+        let (result, _) = render_katex_with_options(r"$x$ .", &opts);
+        assert!(result.ends_with(".</span>"));
+    }
+
+    #[test]
+    fn punctuation_not_glued_by_default() {
+        let (result, _) = render_katex(r"$x$,");
+        assert!(!result.contains("white-space: nowrap"));
+        assert!(result.ends_with(','));
+    }
+
+    #[test]
+    fn align_environment() {
+        let eg = r"\begin{align} a &= b \\ c &= d \end{align}";
+        let result = render_katex(eg).0;
+        assert!(result.len() > eg.len());
+        assert_ne!(eg, result);
+    }
+
+    #[test]
+    fn stray_dollar_inside_environment_is_not_prerendered() {
+        // `$x$` sits entirely inside the align body. The dollar scan must
+        // not render it in place before the environment pass runs, or the
+        // environment would be handed a body containing embedded KaTeX
+        // HTML instead of its own, unmodified source. KaTeX itself accepts
+        // `$...$` inside `\text`, so the intact block renders as one formula.
+        let eg = r"\begin{align} a &= b \\ \text{say $x$ is real} \end{align}";
+        let (result, errors) = render_katex(eg);
+        assert!(errors.is_empty());
+        assert!(result.starts_with(r#"<span class="katex-display">"#));
+    }
+
+    #[test]
+    fn paren_inline_mixed_with_dollar_inline() {
+        let eg = r"Consider \(x\), then $y$.";
+        let (result, errors) = render_katex(eg);
+        assert!(errors.is_empty());
+        assert!(result.contains(", then "));
+        assert!(result.contains("."));
+        assert_ne!(eg, result);
+    }
+
+    #[test]
+    fn paren_inline_disabled() {
+        let eg = r"Consider \(x\).";
+        let opts = KatexOptions {
+            delimiters: DelimiterOptions { paren_inline: false, ..DelimiterOptions::default() },
+            ..KatexOptions::default()
+        };
+        let (result, _) = render_katex_with_options(eg, &opts);
+        assert_eq!(eg, result);
+    }
+
+    #[test]
+    fn escaped_dollar_inside_formula_renders() {
+        // The single-pass scanner tracks escapes within the formula itself,
+        // so a literal `\$` no longer has to break the whole match.
+        let eg = r"$\text{cost: \$5}$";
+        let (result, errors) = render_katex(eg);
+        assert!(errors.is_empty());
+        assert_ne!(eg, result);
+    }
+}
@@ -0,0 +1,106 @@
+//! A small reimplementation of the parts of KaTeX's `mhchem` contrib
+//! extension needed to cover the common `\ce{...}` equation and `\pu{...}`
+//! unit syntax chemistry authors expect: subscripted atom counts, reaction
+//! arrows, bond dashes, and number/unit spacing. This is not a full port of
+//! the JS extension's equation grammar (no stacked/charged bonds, no
+//! isotopes, no state-of-matter annotations), just enough to make ordinary
+//! formulas and units typeset correctly.
+
+use std::collections::HashMap;
+
+use once_cell::sync::Lazy;
+use regex::{Captures, Regex};
+
+/// The `\tripledash` helper macro mhchem uses to draw the short dash found in
+/// triple-bond notation (e.g. `A#B`).
+const TRIPLEDASH: &str = r"\vphantom{-}\raisebox{2pt}{\kern2mu\rule{0.25em}{0.04em}\kern2mu}";
+
+/// Macro definitions registered into the KaTeX macro map when
+/// `KatexOptions::mhchem` is enabled. `\ce`/`\pu` themselves are handled by
+/// [`expand`], which runs ahead of KaTeX, so only their helper macro is
+/// registered here.
+pub fn macros() -> HashMap<String, String> {
+    let mut macros = HashMap::new();
+    macros.insert(r"\tripledash".to_string(), TRIPLEDASH.to_string());
+    macros
+}
+
+static CE_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"\\ce\{([^{}]*)\}").unwrap());
+static PU_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"\\pu\{([^{}]*)\}").unwrap());
+static SUBSCRIPT_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"([A-Za-z)}])(\d+)").unwrap());
+static LETTERS_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"[A-Za-z]+").unwrap());
+static NUMBER_UNIT_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"(\d)\s+([A-Za-z])").unwrap());
+
+/// Rewrite `\ce{...}` and `\pu{...}` spans in `tex` into plain KaTeX/LaTeX
+/// that renders as real chemistry notation, so this can run ahead of KaTeX
+/// rather than depending on it to expand macro-level chemistry syntax.
+pub fn expand(tex: &str) -> String {
+    let tex = CE_RE.replace_all(tex, |caps: &Captures| expand_ce_body(&caps[1]));
+    PU_RE.replace_all(&tex, |caps: &Captures| expand_pu_body(&caps[1])).into_owned()
+}
+
+/// `\ce{...}`: atom counts subscripted and element symbols set upright
+/// first (while the text is still plain chemistry notation), then reaction
+/// arrows and triple-bond dashes swapped in last, so the LaTeX commands
+/// they introduce aren't mistaken for element symbols and re-wrapped.
+fn expand_ce_body(body: &str) -> String {
+    let subscripted = SUBSCRIPT_RE.replace_all(body, "${1}_{$2}");
+    upright(&subscripted)
+        .replace('#', r"\tripledash\tripledash\tripledash ")
+        .replace("<=>", r"\rightleftharpoons ")
+        .replace("<->", r"\leftrightarrow ")
+        .replace("->", r"\rightarrow ")
+        .replace("<-", r"\leftarrow ")
+}
+
+/// `\pu{...}`: a thin space between a quantity and the unit that follows it,
+/// with the unit set upright.
+fn expand_pu_body(body: &str) -> String {
+    upright(&NUMBER_UNIT_RE.replace_all(body, r"$1\,$2"))
+}
+
+/// Wrap runs of letters in `\mathrm{...}` so element symbols and unit names
+/// render upright instead of in math italic.
+fn upright(s: &str) -> String {
+    LETTERS_RE.replace_all(s, r"\mathrm{$0}").into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tripledash_macro_registered() {
+        assert!(macros().contains_key(r"\tripledash"));
+    }
+
+    #[test]
+    fn ce_subscripts_atom_counts() {
+        let out = expand(r"\ce{CO2 + H2O -> H2CO3}");
+        assert!(out.contains(r"\mathrm{CO}_{2}"));
+        assert!(out.contains(r"\mathrm{H}_{2}\mathrm{O}"));
+    }
+
+    #[test]
+    fn ce_renders_reaction_arrow() {
+        let out = expand(r"\ce{A -> B}");
+        assert!(out.contains(r"\rightarrow"));
+    }
+
+    #[test]
+    fn ce_renders_triple_bond_with_tripledash() {
+        let out = expand(r"\ce{A#B}");
+        assert!(out.contains(r"\tripledash"));
+    }
+
+    #[test]
+    fn pu_inserts_thin_space_between_quantity_and_unit() {
+        let out = expand(r"\pu{123 kJ/mol}");
+        assert!(out.contains(r"123\,\mathrm{kJ}/\mathrm{mol}"));
+    }
+
+    #[test]
+    fn leaves_non_mhchem_tex_untouched() {
+        assert_eq!(expand(r"\frac{1}{2}"), r"\frac{1}{2}");
+    }
+}